@@ -1,5 +1,5 @@
-mod memtable;
-mod wal;
+pub mod memtable;
+pub mod wal;
 mod utils;
 
 #[cfg(test)]
@@ -1,12 +1,35 @@
 
+use std::collections::BTreeMap;
+use std::mem;
+
+use smallvec::SmallVec;
+
+/// Values up to this many bytes are stored inline in the entry rather than
+/// in a separate heap allocation.
+pub const INLINE_VALUE_SIZE: usize = 16;
+/// Size at which a table is full by default, see `MemTable::is_full`.
+pub const DEFAULT_MAX_SIZE: usize = 4 * 1024 * 1024;
+
+type Value = SmallVec<[u8; INLINE_VALUE_SIZE]>;
+
+/// In-memory, key-sorted view of the latest write to each key.
+///
+/// `set` and `delete` are applied in the order they were logged and the
+/// latest write to a key always replaces the earlier one, without comparing
+/// timestamps. In particular, of two writes with the same timestamp the one
+/// logged last wins, whether it's a set or a delete. `merge` is the
+/// exception: it keeps the entry with the higher timestamp, and the merged
+/// in table wins a tie.
 pub struct MemTable {
-    entries: Vec<MemTableEntry>,
+    entries: BTreeMap<Vec<u8>, MemTableEntry>,
     size: usize,
+    max_size: usize,
 }
 
+#[derive(Debug, PartialEq)]
 pub struct MemTableEntry {
     pub key: Vec<u8>,
-    pub value: Option<Vec<u8>>,
+    value: Option<Value>,
     pub timestamp: u128,
     pub deleted: bool,
 }
@@ -14,36 +37,36 @@ pub struct MemTableEntry {
 impl MemTable {
     pub fn new() -> MemTable {
         MemTable {
-            entries: Vec::new(),
+            entries: BTreeMap::new(),
             size: 0,
+            max_size: DEFAULT_MAX_SIZE,
         }
     }
 
-    fn get_index(&self, key: &[u8]) -> Result<usize, usize> {
-        self.entries
-            .binary_search_by_key(&key, |e| e.key.as_slice())
+    /// Same as `new`. Kept for callers sizing bulk loads up front, the
+    /// `BTreeMap` backing store allocates per node and has no capacity.
+    pub fn with_capacity(_capacity: usize) -> MemTable {
+        Self::new()
     }
 
+    /// No-op, see `with_capacity`.
+    pub fn reserve(&mut self, _additional: usize) {}
+
     pub fn set(&mut self, key: &[u8], value: &[u8], timestamp: u128) {
         let entry = MemTableEntry {
             key: key.to_owned(),
-            value: Some(value.to_owned()),
+            value: Some(Value::from_slice(value)),
             timestamp,
             deleted: false,
         };
-        match self.get_index(key) {
-            Ok(idx) => {
-                self.entries[idx] = entry;
-                if let Some(v) = self.entries[idx].value.as_ref() {
-                    if value.len() < v.len() {
-                        self.size -= v.len() - value.len();
-                    } else {
-                        self.size += value.len() - v.len();
-                    }
+        match self.entries.insert(key.to_owned(), entry) {
+            Some(old) => {
+                self.size += value.len();
+                if let Some(v) = old.value {
+                    self.size -= v.len();
                 }
             }
-            Err(idx) => {
-                self.entries.insert(idx, entry);
+            None => {
                 self.size += key.len() + value.len() + 16 + 1;
             }
         }
@@ -56,35 +79,117 @@ impl MemTable {
             timestamp,
             deleted: true,
         };
-        match self.get_index(key) {
-            Ok(idx) => {
-                self.entries[idx] = entry;
-                if let Some(v) = self.entries[idx].value.as_ref() {
+        match self.entries.insert(key.to_owned(), entry) {
+            Some(old) => {
+                if let Some(v) = old.value {
                     self.size -= v.len();
                 }
             }
-            Err(idx) => {
-                self.entries.insert(idx, entry);
+            None => {
                 self.size += key.len() + 16 + 1;
             }
         }
     }
 
+    /// Folds `other` into this table. Where both have a key, the entry with
+    /// the higher timestamp is kept. On a tie `other` wins, as if its writes
+    /// were applied after this table's.
+    pub fn merge(&mut self, other: MemTable) {
+        for (key, entry) in other.entries {
+            if let Some(existing) = self.entries.get(&key) {
+                if existing.timestamp > entry.timestamp {
+                    continue;
+                }
+            }
+            self.size += entry.size();
+            if let Some(old) = self.entries.insert(key, entry) {
+                self.size -= old.size();
+            }
+        }
+    }
+
     pub fn get(&self, key: &[u8]) -> Option<&MemTableEntry> {
-        if let Ok(idx) = self.get_index(key) {
-            return Some(&self.entries[idx]);
+        self.entries.get(key)
+    }
+
+    /// Returns the value of a live key, `None` if the key is missing or was
+    /// deleted.
+    pub fn get_value(&self, key: &[u8]) -> Option<&[u8]> {
+        match self.entries.get(key) {
+            Some(entry) if !entry.deleted => entry.value.as_deref(),
+            _ => None,
         }
-        None
+    }
+
+    /// Iterates the entries in key order.
+    pub fn iter(&self) -> impl Iterator<Item = &MemTableEntry> {
+        self.entries.values()
     }
 
     pub fn len(&self) -> usize {
         self.entries.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Bytes held by the entries: keys, values, timestamps and tombstones.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Sets the size at which the table counts as full. Defaults to
+    /// `DEFAULT_MAX_SIZE`.
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.size >= self.max_size
+    }
+
+    /// If the table is full, moves its entries into a new table, returned
+    /// for flushing, and leaves this one empty. The entries aren't copied.
+    pub fn freeze_if_full(&mut self) -> Option<MemTable> {
+        if !self.is_full() {
+            return None;
+        }
+        Some(MemTable {
+            entries: mem::take(&mut self.entries),
+            size: mem::replace(&mut self.size, 0),
+            max_size: self.max_size,
+        })
+    }
+}
+
+impl MemTableEntry {
+    /// The value, `None` for a tombstone.
+    pub fn value(&self) -> Option<&[u8]> {
+        self.value.as_deref()
+    }
+
+    /// Bytes this entry counts for in `MemTable::size`.
+    fn size(&self) -> usize {
+        let value_len = self.value.as_ref().map_or(0, |v| v.len());
+        self.key.len() + value_len + 16 + 1
+    }
+}
+
+impl Default for MemTable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::memtable::MemTable;
+    use crate::memtable::{MemTable, MemTableEntry, INLINE_VALUE_SIZE};
+    use std::time::{Duration, Instant};
+
+    fn nth(mem_table: &MemTable, n: usize) -> &MemTableEntry {
+        mem_table.entries.values().nth(n).unwrap()
+    }
 
     #[test]
     fn test_mem_table_get_not_exists() {
@@ -93,7 +198,7 @@ mod tests {
 
         let res = mem_table.get(b"key 2");
 
-        assert_eq!(res.is_some(), false);
+        assert!(res.is_none());
     }
 
     #[test]
@@ -104,12 +209,24 @@ mod tests {
 
         let res = mem_table.get(b"key 2");
 
-        assert_eq!(res.is_some(), true);
+        assert!(res.is_some());
         let res = res.unwrap();
         assert_eq!(res.key, b"key 2");
-        assert_eq!(res.value.as_ref().unwrap(), b"some value");
+        assert_eq!(res.value.as_deref().unwrap(), b"some value");
         assert_eq!(res.timestamp, 1);
-        assert_eq!(res.deleted, false);
+        assert!(!res.deleted);
+    }
+
+    #[test]
+    fn test_mem_table_get_value() {
+        let mut mem_table = MemTable::new();
+        mem_table.set(b"key 1", b"value", 0);
+        mem_table.set(b"key 2", b"value", 1);
+        mem_table.delete(b"key 2", 2);
+
+        assert_eq!(mem_table.get_value(b"key 1"), Some(&b"value"[..]));
+        assert_eq!(mem_table.get_value(b"key 2"), None);
+        assert_eq!(mem_table.get_value(b"key 3"), None);
     }
 
     #[test]
@@ -123,13 +240,13 @@ mod tests {
         assert_eq!(entry.key, b"key 2");
         assert_eq!(entry.value, None);
         assert_eq!(entry.timestamp, 1);
-        assert_eq!(entry.deleted, true);
+        assert!(entry.deleted);
 
         assert_eq!(mem_table.len(), 2);
-        assert_eq!(mem_table.entries[1].key, b"key 2");
-        assert_eq!(mem_table.entries[1].value, None);
-        assert_eq!(mem_table.entries[1].timestamp, 1);
-        assert_eq!(mem_table.entries[1].deleted, true);
+        assert_eq!(nth(&mem_table, 1).key, b"key 2");
+        assert_eq!(nth(&mem_table, 1).value, None);
+        assert_eq!(nth(&mem_table, 1).timestamp, 1);
+        assert!(nth(&mem_table, 1).deleted);
     }
 
     #[test]
@@ -143,13 +260,13 @@ mod tests {
         assert_eq!(entry.key, b"key 1");
         assert_eq!(entry.value, None);
         assert_eq!(entry.timestamp, 1);
-        assert_eq!(entry.deleted, true);
+        assert!(entry.deleted);
 
         assert_eq!(mem_table.len(), 1);
-        assert_eq!(mem_table.entries[0].key, b"key 1");
-        assert_eq!(mem_table.entries[0].value, None);
-        assert_eq!(mem_table.entries[0].timestamp, 1);
-        assert_eq!(mem_table.entries[0].deleted, true);
+        assert_eq!(nth(&mem_table, 0).key, b"key 1");
+        assert_eq!(nth(&mem_table, 0).value, None);
+        assert_eq!(nth(&mem_table, 0).timestamp, 1);
+        assert!(nth(&mem_table, 0).deleted);
     }
 
     #[test]
@@ -159,10 +276,162 @@ mod tests {
         mem_table.set(b"key 1", b"some value", 0);
 
         assert_eq!(mem_table.len(), 1);
-        assert_eq!(mem_table.entries[0].key, b"key 1");
-        assert_eq!(mem_table.entries[0].value.as_ref().unwrap(), b"some value");
-        assert_eq!(mem_table.entries[0].timestamp, 0);
-        assert_eq!(mem_table.entries[0].deleted, false);
+        assert_eq!(nth(&mem_table, 0).key, b"key 1");
+        assert_eq!(nth(&mem_table, 0).value.as_deref().unwrap(), b"some value");
+        assert_eq!(nth(&mem_table, 0).timestamp, 0);
+        assert!(!nth(&mem_table, 0).deleted);
+    }
+
+    #[test]
+    fn test_mem_table_reserve() {
+        let mut mem_table = MemTable::with_capacity(10);
+
+        mem_table.reserve(100);
+        for i in 0..100u32 {
+            mem_table.set(&i.to_be_bytes(), b"value", i as u128);
+        }
+
+        assert_eq!(mem_table.len(), 100);
+        assert_eq!(nth(&mem_table, 42).key, 42u32.to_be_bytes());
+    }
+
+    #[test]
+    fn test_mem_table_size_on_override() {
+        let mut mem_table = MemTable::new();
+        mem_table.set(b"key 1", b"value", 0);
+
+        mem_table.set(b"key 1", b"longer value", 1);
+        assert_eq!(mem_table.size, 5 + 12 + 16 + 1);
+        mem_table.delete(b"key 1", 2);
+        assert_eq!(mem_table.size, 5 + 16 + 1);
+        mem_table.set(b"key 1", b"value", 3);
+        assert_eq!(mem_table.size, 5 + 5 + 16 + 1);
+    }
+
+    #[test]
+    #[ignore = "timing based, run with --ignored on an idle machine"]
+    fn test_mem_table_insert_throughput() {
+        // Descending keys always land at the front, which is the worst case
+        // for a sorted vector: 4x the entries would take ~16x the time.
+        fn fill(n: u32) -> Duration {
+            let mut mem_table = MemTable::new();
+            let start = Instant::now();
+            for i in (0..n).rev() {
+                mem_table.set(&i.to_be_bytes(), b"value", i as u128);
+            }
+            start.elapsed()
+        }
+
+        let small = (0..3).map(|_| fill(25_000)).min().unwrap();
+        let large = (0..3).map(|_| fill(100_000)).min().unwrap();
+
+        assert!(large < small * 10, "{:?} vs {:?}", large, small);
+    }
+
+    #[test]
+    fn test_mem_table_set_inline_and_heap_values() {
+        let mut mem_table = MemTable::new();
+        let small = [7u8; INLINE_VALUE_SIZE];
+        let large = [7u8; INLINE_VALUE_SIZE + 1];
+
+        mem_table.set(b"key 1", &small, 0);
+        mem_table.set(b"key 2", &large, 1);
+
+        let entry = mem_table.get(b"key 1").unwrap();
+        assert!(!entry.value.as_ref().unwrap().spilled());
+        assert_eq!(entry.value.as_deref().unwrap(), &small[..]);
+        let entry = mem_table.get(b"key 2").unwrap();
+        assert!(entry.value.as_ref().unwrap().spilled());
+        assert_eq!(entry.value.as_deref().unwrap(), &large[..]);
+        assert_eq!(mem_table.size, 2 * (5 + 16 + 1) + small.len() + large.len());
+    }
+
+    #[test]
+    fn test_mem_table_binary_keys() {
+        let mut mem_table = MemTable::new();
+        let keys: [&[u8]; 7] = [
+            b"abc", b"\xff\xfe", b"a\0c", b"", b"ab", b"\n", b"ab\0",
+        ];
+        for (i, key) in keys.iter().enumerate() {
+            mem_table.set(key, key, i as u128);
+        }
+
+        let sorted: Vec<&[u8]> = mem_table.entries.values()
+            .map(|e| e.key.as_slice())
+            .collect();
+        assert_eq!(sorted, vec![
+            &b""[..], b"\n", b"a\0c", b"ab", b"ab\0", b"abc", b"\xff\xfe",
+        ]);
+        for key in keys.iter() {
+            let entry = mem_table.get(key).unwrap();
+            assert_eq!(entry.key, *key);
+            assert_eq!(entry.value.as_deref().unwrap(), *key);
+        }
+        assert!(mem_table.get(b"a").is_none());
+        assert!(mem_table.get(b"ab\0\0").is_none());
+    }
+
+    #[test]
+    fn test_mem_table_same_timestamp_last_write_wins() {
+        let mut mem_table = MemTable::new();
+
+        mem_table.set(b"key 1", b"value 1", 5);
+        mem_table.set(b"key 1", b"value 2", 5);
+        mem_table.set(b"key 2", b"value", 5);
+        mem_table.delete(b"key 2", 5);
+        mem_table.delete(b"key 3", 5);
+        mem_table.set(b"key 3", b"value", 5);
+
+        assert_eq!(mem_table.get(b"key 1").unwrap().value.as_deref().unwrap(),
+                   b"value 2");
+        assert!(mem_table.get(b"key 2").unwrap().deleted);
+        assert!(!mem_table.get(b"key 3").unwrap().deleted);
+    }
+
+    #[test]
+    fn test_mem_table_merge() {
+        let mut mem_table = MemTable::new();
+        mem_table.set(b"key 1", b"old value", 1);
+        mem_table.set(b"key 2", b"newer value", 5);
+        mem_table.set(b"key 3", b"value", 1);
+        let mut other = MemTable::new();
+        other.set(b"key 1", b"new value", 2);
+        other.set(b"key 2", b"old", 3);
+        other.delete(b"key 3", 4);
+        other.set(b"key 4", b"value", 1);
+
+        mem_table.merge(other);
+
+        let mut expected = MemTable::new();
+        expected.set(b"key 1", b"new value", 2);
+        expected.set(b"key 2", b"newer value", 5);
+        expected.delete(b"key 3", 4);
+        expected.set(b"key 4", b"value", 1);
+        assert_eq!(mem_table.len(), 4);
+        for (key, entry) in expected.entries.iter() {
+            let merged = mem_table.get(key).unwrap();
+            assert_eq!(merged.value, entry.value);
+            assert_eq!(merged.timestamp, entry.timestamp);
+            assert_eq!(merged.deleted, entry.deleted);
+        }
+        assert_eq!(mem_table.size, expected.size);
+    }
+
+    #[test]
+    fn test_mem_table_merge_compares_decoded_timestamps() {
+        // 255 serializes as ff 00 .. and 256 as 00 01 .. in little-endian, so
+        // comparing raw bytes would get these backwards.
+        let mut mem_table = MemTable::new();
+        mem_table.set(b"key 1", b"old value", 255);
+        mem_table.set(b"key 2", b"new value", 256);
+        let mut other = MemTable::new();
+        other.set(b"key 1", b"new value", 256);
+        other.set(b"key 2", b"old value", 255);
+
+        mem_table.merge(other);
+
+        assert_eq!(mem_table.get_value(b"key 1"), Some(&b"new value"[..]));
+        assert_eq!(mem_table.get_value(b"key 2"), Some(&b"new value"[..]));
     }
 
     #[test]
@@ -173,10 +442,34 @@ mod tests {
         mem_table.set(b"key 1", b"some value upd", 1);
 
         assert_eq!(mem_table.len(), 1);
-        assert_eq!(mem_table.entries[0].key, b"key 1");
-        assert_eq!(mem_table.entries[0].value.as_ref().unwrap(),
+        assert_eq!(nth(&mem_table, 0).key, b"key 1");
+        assert_eq!(nth(&mem_table, 0).value.as_deref().unwrap(),
                    b"some value upd");
-        assert_eq!(mem_table.entries[0].timestamp, 1);
-        assert_eq!(mem_table.entries[0].deleted, false);
+        assert_eq!(nth(&mem_table, 0).timestamp, 1);
+        assert!(!nth(&mem_table, 0).deleted);
+    }
+
+    #[test]
+    fn test_mem_table_freeze_if_full() {
+        let mut mem_table = MemTable::new();
+        mem_table.set_max_size(3 * (5 + 5 + 16 + 1));
+        mem_table.set(b"key 1", b"value", 0);
+        mem_table.set(b"key 2", b"value", 1);
+        assert!(mem_table.freeze_if_full().is_none());
+        mem_table.delete(b"key 3", 2);
+        mem_table.set(b"key 4", b"value", 3);
+        let size = mem_table.size();
+
+        let frozen = mem_table.freeze_if_full().unwrap();
+
+        assert_eq!(frozen.len(), 4);
+        assert_eq!(frozen.size(), size);
+        assert_eq!(frozen.get_value(b"key 1"), Some(&b"value"[..]));
+        assert!(frozen.get(b"key 3").unwrap().deleted);
+        assert_eq!(frozen.get_value(b"key 4"), Some(&b"value"[..]));
+        assert!(mem_table.is_empty());
+        assert_eq!(mem_table.size(), 0);
+        assert!(!mem_table.is_full());
+        assert!(mem_table.freeze_if_full().is_none());
     }
 }
\ No newline at end of file
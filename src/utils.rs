@@ -1,14 +1,88 @@
+use std::ffi::OsStr;
 use std::fs::read_dir;
+use std::io::Result as IoResult;
 use std::path::{Path, PathBuf};
 
-pub fn get_files_by_ext(dir: &Path, ext: &str) -> Vec<PathBuf> {
+pub fn get_files_by_ext(dir: &Path, ext: &str) -> IoResult<Vec<PathBuf>> {
     let mut files = Vec::new();
-    for file in read_dir(dir).unwrap() {
-        let path = file.unwrap().path();
-        if path.extension().unwrap() == ext {
+    for file in read_dir(dir)? {
+        let path = file?.path();
+        if path.extension() == Some(OsStr::new(ext)) {
             files.push(path);
         }
     }
 
-    files
+    Ok(files)
+}
+
+/// Fsyncs the directory itself, so that newly created file entries in it
+/// survive a crash. Only supported on Unix, a no-op elsewhere.
+pub fn fsync_dir(dir: &Path) -> IoResult<()> {
+    #[cfg(test)]
+    SYNCED_DIRS.with(|dirs| dirs.borrow_mut().push(dir.to_owned()));
+    sync_dir(dir)
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> IoResult<()> {
+    std::fs::File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> IoResult<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Directories passed to `fsync_dir` by the current test.
+    static SYNCED_DIRS: std::cell::RefCell<Vec<PathBuf>> =
+        const { std::cell::RefCell::new(Vec::new()) };
+}
+
+/// Directories passed to `fsync_dir` by the current test so far.
+#[cfg(test)]
+pub fn synced_dirs() -> Vec<PathBuf> {
+    SYNCED_DIRS.with(|dirs| dirs.borrow().clone())
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC-32 (IEEE) of `data`, continuing from `crc`. Pass 0 to start, so that
+/// `crc32(crc32(0, a), b) == crc32(0, ab)`.
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc = CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(utils::crc32(0, b""), 0);
+        assert_eq!(utils::crc32(0, b"123456789"), 0xcbf4_3926);
+        assert_eq!(utils::crc32(utils::crc32(0, b"1234"), b"56789"),
+                   0xcbf4_3926);
+    }
 }
@@ -1,15 +1,23 @@
+use crate::utils;
 use crate::wal;
+use std::error::Error;
+use std::fmt;
 use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
-use std::io::{BufReader, Result as IoResult};
+use std::io::{
+    BufReader, Error as IoError, ErrorKind, Result as IoResult, SeekFrom,
+};
 use std::path::{PathBuf};
 
 /* WAL entry has the following format:
-+----------------+-----------------+-------------+-----+-------------+-------+
-| tombstone (1B) | timestamp (16B) | k_size (8B) | key | v_size (8B) | value |
-+----------------+-----------------+-------------+-----+-------------+-------+
++------------+-----------------+-------------+-----+-------------+-------+
+| flags (1B) | timestamp (16B) | k_size (8B) | key | v_size (8B) | value |
++------------+-----------------+-------------+-----+-------------+-------+
 
-    tombstone = If this record was deleted and has a value.
+    flags = Record attributes. Bit 0 is set if this record was deleted, in
+        which case v_size and value are omitted: a tombstone never carries a
+        value. Other bits are reserved. Files written before flags existed
+        stored a 0/1 tombstone byte here, which decodes the same way.
     timestamp = Timestamp of the operation in microseconds.
     k_size = Length of the Key data.
     key = Key data.
@@ -17,56 +25,150 @@ use std::path::{PathBuf};
     value = Value data.
  */
 
+/// Returned (wrapped in an `UnexpectedEof` IO error) when a record claims
+/// more bytes than are left in the segment.
+#[derive(Debug, PartialEq)]
+pub struct TruncatedRecord {
+    /// Offset of the start of the truncated record.
+    pub offset: u64,
+    /// Number of bytes the record needed at the point it was cut off.
+    pub claimed: u64,
+    /// Number of bytes that were actually left in the segment.
+    pub remaining: u64,
+}
+
+impl fmt::Display for TruncatedRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "truncated WAL record at offset {}: needs {} bytes, {} left",
+               self.offset, self.claimed, self.remaining)
+    }
+}
+
+impl Error for TruncatedRecord {}
+
 pub struct WalIterator {
     reader: BufReader<File>,
+    format: wal::SegmentFormat,
+    len: u64,
+    pos: u64,
+    /// End of the frame being read, or `len` outside of a frame.
+    limit: u64,
+    /// Running CRC of the frame body being read.
+    crc: Option<u32>,
+    record_offset: u64,
 }
 
 impl WalIterator {
 
+    /// Opens the segment at `path`. Fails with `NotAWalFile` if it doesn't
+    /// start with a valid segment header or, for segments written before the
+    /// magic existed, an entry.
     pub fn new(path: PathBuf) -> IoResult<WalIterator> {
-        let file = OpenOptions::new().read(true).open(path)?;
+        let mut file = OpenOptions::new().read(true).open(&path)?;
+        let len = file.metadata()?.len();
+        let (format, pos) = wal::read_segment_header(&mut file, &path)?;
         let reader = BufReader::new(file);
-        Ok(WalIterator { reader })
+        Ok(WalIterator {
+            reader,
+            format,
+            len,
+            pos,
+            limit: len,
+            crc: None,
+            record_offset: pos,
+        })
     }
 
-    fn read_size(&mut self) -> Option<usize> {
-        let mut buff = [0; 8];
-        match self.reader.read_exact(&mut buff) {
-            Ok(()) => { Some(usize::from_le_bytes(buff)) },
-            Err(_) => { None },
-        }
+    /// Offset just past the header or the last record read successfully.
+    pub fn position(&self) -> u64 {
+        self.record_offset
     }
 
-    fn read_vec(&mut self, size: usize) -> Option<Vec<u8>> {
-        let mut result = vec![0; size];
-        match self.reader.read_exact(&mut result) {
-            Ok(()) => { Some(result) },
-            Err(_) => { None },
-        }
+    /// How k_size and v_size are encoded in this segment.
+    pub fn encoding(&self) -> wal::LengthEncoding {
+        self.format.encoding()
     }
 
-    fn read_bool(&mut self) -> Option<bool> {
-        let mut buff = [0; 1];
-        match self.reader.read_exact(&mut buff) {
-            Ok(()) => { Some(buff[0] != 0) },
-            Err(_) => { None },
+    /// Turns this into an iterator that reports corrupt records instead of
+    /// stopping at them.
+    pub fn records(self) -> WalRecords {
+        WalRecords { iter: Some(self), error: None, done: false }
+    }
+
+    /// Picks up records appended since the segment was opened or last
+    /// resumed, continuing after the last complete record. Lets a reader
+    /// follow a segment that another handle is still writing: a record that
+    /// was only partly written is read again in full once it's complete.
+    /// Fails if the segment shrank below the last complete record.
+    pub fn resume(&mut self) -> IoResult<()> {
+        let len = self.reader.get_ref().metadata()?.len();
+        if len < self.record_offset {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                format!("WAL segment shrank to {} bytes, below offset {}",
+                        len, self.record_offset),
+            ));
         }
+        self.len = len;
+        self.reader.seek(SeekFrom::Start(self.record_offset))?;
+        self.pos = self.record_offset;
+        self.limit = self.len;
+        self.crc = None;
+        Ok(())
     }
 
-    fn read_timestamp(&mut self) -> Option<u128> {
-        let mut buff = [0; 16];
-        match self.reader.read_exact(&mut buff) {
-            Ok(()) => { Some(u128::from_le_bytes(buff)) },
-            Err(_) => { None },
+    /// Reads the next record. Returns `Ok(None)` at a clean end of the
+    /// segment and a `TruncatedRecord` error if the segment ends partway
+    /// through a record. Unless the segment is headerless, the whole frame
+    /// must be present before any field is decoded, and the body is checked
+    /// against its CRC.
+    pub fn read_entry(&mut self) -> IoResult<Option<wal::WalEntry>> {
+        if self.pos == self.len {
+            return Ok(None);
+        }
+        if self.format == wal::SegmentFormat::Headerless {
+            let entry = self.read_body()?;
+            self.record_offset = self.pos;
+            return Ok(Some(entry));
+        }
+
+        let mut buff = [0; 4];
+        self.read_exact(&mut buff)?;
+        let body_size = u32::from_le_bytes(buff) as u64;
+        self.check_remaining(body_size + 4)?;
+        self.limit = self.pos + body_size;
+        self.crc = Some(0);
+        let entry = self.read_body();
+        let crc = self.crc.take();
+        self.limit = self.len;
+        // The frame is complete, so running out of bytes means the entry
+        // overruns it rather than a truncated segment.
+        let entry = entry.map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => self.corrupt("entry overruns frame"),
+            _ => e,
+        })?;
+        if self.pos != self.record_offset + 4 + body_size {
+            return Err(self.corrupt("entry is shorter than its frame"));
         }
+        self.read_exact(&mut buff)?;
+        if crc != Some(u32::from_le_bytes(buff)) {
+            return Err(self.corrupt("checksum mismatch"));
+        }
+
+        self.record_offset = self.pos;
+        Ok(Some(entry))
     }
-}
 
-impl Iterator for WalIterator {
-    type Item = wal::WalEntry;
-    
-    fn next(&mut self) -> Option<Self::Item> {
-        let deleted = self.read_bool()?;
+    fn read_body(&mut self) -> IoResult<wal::WalEntry> {
+        let flags = self.read_flags()?;
+        if flags & !wal::KNOWN_FLAGS != 0 {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                format!("unknown WAL record flags {:#x} at offset {}",
+                        flags, self.record_offset),
+            ));
+        }
+        let deleted = flags & wal::FLAG_DELETED != 0;
         let timestamp = self.read_timestamp()?;
         let key_size = self.read_size()?;
         let key = self.read_vec(key_size)?;
@@ -74,12 +176,158 @@ impl Iterator for WalIterator {
         if !deleted {
             let val_size = self.read_size()?;
             value = Option::from(self.read_vec(val_size)?);
+        } else if self.format != wal::SegmentFormat::Headerless
+            && self.pos != self.limit {
+            return Err(self.corrupt("tombstone carries a value"));
         }
-        Some(wal::WalEntry {
+        Ok(wal::WalEntry {
             key,
             value,
             timestamp,
             deleted,
         })
     }
-}
\ No newline at end of file
+
+    fn corrupt(&self, reason: &str) -> IoError {
+        IoError::new(
+            ErrorKind::InvalidData,
+            format!("corrupt WAL entry at offset {}: {}",
+                    self.record_offset, reason),
+        )
+    }
+
+    /// Fails with `TruncatedRecord` if fewer than `size` bytes are left.
+    fn check_remaining(&self, size: u64) -> IoResult<()> {
+        let remaining = self.limit - self.pos;
+        if size > remaining {
+            return Err(IoError::new(ErrorKind::UnexpectedEof, TruncatedRecord {
+                offset: self.record_offset,
+                claimed: size,
+                remaining,
+            }));
+        }
+        Ok(())
+    }
+
+    fn read_exact(&mut self, buff: &mut [u8]) -> IoResult<()> {
+        self.check_remaining(buff.len() as u64)?;
+        self.reader.read_exact(buff)?;
+        self.pos += buff.len() as u64;
+        if let Some(crc) = self.crc {
+            self.crc = Some(utils::crc32(crc, buff));
+        }
+        Ok(())
+    }
+
+    fn read_size(&mut self) -> IoResult<usize> {
+        match self.format.encoding() {
+            wal::LengthEncoding::Fixed64 => {
+                let mut buff = [0; 8];
+                self.read_exact(&mut buff)?;
+                Ok(usize::from_le_bytes(buff))
+            }
+            wal::LengthEncoding::Fixed32 => {
+                let mut buff = [0; 4];
+                self.read_exact(&mut buff)?;
+                Ok(u32::from_le_bytes(buff) as usize)
+            }
+            wal::LengthEncoding::Varint => {
+                let mut size = 0;
+                for shift in (0..64).step_by(7) {
+                    let mut buff = [0; 1];
+                    self.read_exact(&mut buff)?;
+                    size |= ((buff[0] & 0x7f) as usize) << shift;
+                    if buff[0] & 0x80 == 0 {
+                        return Ok(size);
+                    }
+                }
+                Err(IoError::new(
+                    ErrorKind::InvalidData,
+                    format!("varint length too long at offset {}",
+                            self.record_offset),
+                ))
+            }
+        }
+    }
+
+    fn read_vec(&mut self, size: usize) -> IoResult<Vec<u8>> {
+        // Checked before allocating, a corrupt size could be huge.
+        self.check_remaining(size as u64)?;
+        let mut result = vec![0; size];
+        self.read_exact(&mut result)?;
+        Ok(result)
+    }
+
+    fn read_flags(&mut self) -> IoResult<u8> {
+        let mut buff = [0; 1];
+        self.read_exact(&mut buff)?;
+        Ok(buff[0])
+    }
+
+    fn read_timestamp(&mut self) -> IoResult<u128> {
+        let mut buff = [0; 16];
+        self.read_exact(&mut buff)?;
+        Ok(u128::from_le_bytes(buff))
+    }
+}
+
+/// Stops at the end of the segment or at the first record that can't be
+/// read, whatever the reason. Meant for following a live segment, see
+/// `resume`. Use `records` where corruption must not go unnoticed.
+impl Iterator for WalIterator {
+    type Item = wal::WalEntry;
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_entry().ok().flatten()
+    }
+}
+
+/// Fallible iterator over the records of a segment, see
+/// `WalIterator::records`. A record cut off by the end of the segment, as
+/// left by a crash mid-write, ends the iteration like a clean end does. Any
+/// other error is returned once and ends the iteration.
+pub struct WalRecords {
+    iter: Option<WalIterator>,
+    error: Option<IoError>,
+    done: bool,
+}
+
+impl WalRecords {
+    /// An iterator that only returns `error`, for a segment that couldn't be
+    /// opened.
+    pub(crate) fn failed(error: IoError) -> WalRecords {
+        WalRecords { iter: None, error: Some(error), done: false }
+    }
+
+    /// Offset just past the header or the last record read successfully.
+    pub fn position(&self) -> u64 {
+        self.iter.as_ref().map_or(0, |iter| iter.position())
+    }
+}
+
+impl Iterator for WalRecords {
+    type Item = IoResult<wal::WalEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            self.done = true;
+            return Some(Err(e));
+        }
+        if self.done {
+            return None;
+        }
+        match self.iter.as_mut()?.read_entry() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                let truncated = e.get_ref()
+                    .is_some_and(|e| e.is::<TruncatedRecord>());
+                if truncated { None } else { Some(Err(e)) }
+            }
+        }
+    }
+}
@@ -1,26 +1,207 @@
 mod iterator;
 
-use std::fs::{File, OpenOptions, remove_file};
-use std::io::{BufWriter, Write, Result as IoResult};
+pub use iterator::{TruncatedRecord, WalIterator, WalRecords};
+
+use std::fs::{self, File, OpenOptions, remove_file};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::io::{
+    BufWriter, Error as IoError, ErrorKind, Read, Write, Result as IoResult,
+    Seek, SeekFrom,
+};
 use std::path::{Path, PathBuf};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::memtable;
 use crate::utils;
 
-/* WAL entry has the following format:
-+----------------+-----------------+-------------+-----+-------------+-------+
-| tombstone (1B) | timestamp (16B) | k_size (8B) | key | v_size (8B) | value |
-+----------------+-----------------+-------------+-----+-------------+-------+
+/* A WAL segment starts with a header followed by its framed entries:
++------------+--------------+---------------+---------+---------+-----+
+| magic (4B) | version (1B) | encoding (1B) | frame 1 | frame 2 | ... |
++------------+--------------+---------------+---------+---------+-----+
+
+    magic = SEGMENT_MAGIC, identifies the file as a WAL segment.
+    version = SEGMENT_VERSION, the version of the format below.
+    encoding = Id of the LengthEncoding used for k_size and v_size.
+
+   Each entry is wrapped in a frame:
++-----------------+-------+------------+
+| body_size (4B)  | body  | crc (4B)   |
++-----------------+-------+------------+
+
+    body_size = Length of the body.
+    body = The entry in the format below.
+    crc = CRC-32 of the body.
 
-    tombstone = If this record was deleted and has a value.
+   Segments written before the magic existed are still read, and appended to
+   in their own format. They have no header and start straight with an
+   unframed Fixed64 entry, whose flags byte is 0 or 1.
+
+   WAL entry has the following format:
++------------+-----------------+-------------+-----+-------------+-------+
+| flags (1B) | timestamp (16B) | k_size (8B) | key | v_size (8B) | value |
++------------+-----------------+-------------+-----+-------------+-------+
+
+    flags = Record attributes. Bit 0 is set if this record was deleted, in
+        which case v_size and value are omitted: a tombstone never carries a
+        value. Other bits are reserved. Files written before flags existed
+        stored a 0/1 tombstone byte here, which decodes the same way.
     timestamp = Timestamp of the operation in microseconds.
-    k_size = Length of the Key data.
+    k_size = Length of the Key data, encoded as per the segment header.
     key = Key data.
-    v_size = Length of the Value data.
+    v_size = Length of the Value data, encoded as per the segment header.
     value = Value data.
  */
 
+/// First bytes of every WAL segment.
+pub const SEGMENT_MAGIC: [u8; 4] = *b"RWAL";
+/// Version of the segment format this build reads and writes.
+pub const SEGMENT_VERSION: u8 = 1;
+/// Length of the segment header.
+const SEGMENT_HEADER_LEN: usize = 6;
+
+/// Set in the flags byte of a deleted record.
+pub const FLAG_DELETED: u8 = 1;
+/// Flag bits this version knows how to decode.
+pub const KNOWN_FLAGS: u8 = FLAG_DELETED;
+
+/// How k_size and v_size are encoded in a segment.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LengthEncoding {
+    /// 8 byte little-endian integer.
+    #[default]
+    Fixed64,
+    /// 4 byte little-endian integer, for keys and values under 4 GiB.
+    Fixed32,
+    /// LEB128 varint, 1 byte for lengths under 128.
+    Varint,
+}
+
+impl LengthEncoding {
+    fn id(self) -> u8 {
+        match self {
+            LengthEncoding::Fixed64 => 0,
+            LengthEncoding::Fixed32 => 1,
+            LengthEncoding::Varint => 2,
+        }
+    }
+
+    fn encode(self, size: usize, buff: &mut Vec<u8>) -> IoResult<()> {
+        match self {
+            LengthEncoding::Fixed64 => {
+                buff.extend_from_slice(&size.to_le_bytes());
+            }
+            LengthEncoding::Fixed32 => {
+                let size = u32::try_from(size).map_err(|_| IoError::new(
+                    ErrorKind::InvalidInput,
+                    format!("size {} doesn't fit a Fixed32 length", size),
+                ))?;
+                buff.extend_from_slice(&size.to_le_bytes());
+            }
+            LengthEncoding::Varint => {
+                let mut size = size;
+                while size >= 0x80 {
+                    buff.push(size as u8 | 0x80);
+                    size >>= 7;
+                }
+                buff.push(size as u8);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returned (wrapped in an `InvalidData` IO error) when a `.wal` file
+/// doesn't start with a valid segment header.
+#[derive(Debug, PartialEq)]
+pub struct NotAWalFile {
+    pub path: PathBuf,
+}
+
+impl fmt::Display for NotAWalFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} is not a WAL segment", self.path.display())
+    }
+}
+
+impl Error for NotAWalFile {}
+
+/// Layout of a segment's entries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SegmentFormat {
+    /// Framed entries, with lengths encoded as recorded in the header.
+    Framed(LengthEncoding),
+    /// Segment written before the magic existed: no header and unframed
+    /// entries with `Fixed64` lengths.
+    Headerless,
+}
+
+impl SegmentFormat {
+    fn encoding(self) -> LengthEncoding {
+        match self {
+            SegmentFormat::Framed(encoding) => encoding,
+            SegmentFormat::Headerless => LengthEncoding::Fixed64,
+        }
+    }
+}
+
+fn segment_header(encoding: LengthEncoding) -> [u8; SEGMENT_HEADER_LEN] {
+    let mut header = [0; SEGMENT_HEADER_LEN];
+    header[..4].copy_from_slice(&SEGMENT_MAGIC);
+    header[4] = SEGMENT_VERSION;
+    header[5] = encoding.id();
+    header
+}
+
+fn encoding_from_id(id: u8) -> Option<LengthEncoding> {
+    match id {
+        0 => Some(LengthEncoding::Fixed64),
+        1 => Some(LengthEncoding::Fixed32),
+        2 => Some(LengthEncoding::Varint),
+        _ => None,
+    }
+}
+
+/// Reads the header of the segment at `path` from the start of `file`,
+/// leaving `file` positioned at the first entry. Returns the segment's
+/// format and the length of its header.
+fn read_segment_header(file: &mut File,
+                       path: &Path) -> IoResult<(SegmentFormat, u64)> {
+    let not_a_wal = || IoError::new(
+        ErrorKind::InvalidData,
+        NotAWalFile { path: path.to_owned() },
+    );
+    let len = file.metadata()?.len();
+    let mut head = vec![0; len.min(SEGMENT_HEADER_LEN as u64) as usize];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut head)?;
+
+    let (format, header_len) = match head.first() {
+        None => return Err(not_a_wal()),
+        Some(_) if head.starts_with(&SEGMENT_MAGIC) => {
+            if head.len() < SEGMENT_HEADER_LEN {
+                return Err(not_a_wal());
+            }
+            if head[4] != SEGMENT_VERSION {
+                return Err(IoError::new(
+                    ErrorKind::InvalidData,
+                    format!("unsupported WAL segment version {} in {}",
+                            head[4], path.display()),
+                ));
+            }
+            let encoding = encoding_from_id(head[5]).ok_or_else(not_a_wal)?;
+            (SegmentFormat::Framed(encoding), SEGMENT_HEADER_LEN)
+        }
+        Some(0) | Some(1) => (SegmentFormat::Headerless, 0),
+        Some(_) => return Err(not_a_wal()),
+    };
+    file.seek(SeekFrom::Start(header_len as u64))?;
+
+    Ok((format, header_len as u64))
+}
+
 #[derive(Debug)]
 pub struct WalEntry {
     pub key: Vec<u8>,
@@ -29,103 +210,1617 @@ pub struct WalEntry {
     pub deleted: bool,
 }
 
+impl WalEntry {
+    pub fn is_tombstone(&self) -> bool {
+        self.deleted
+    }
+
+    /// Splits the entry into its key and value, `None` for a tombstone.
+    pub fn into_kv(self) -> (Vec<u8>, Option<Vec<u8>>) {
+        (self.key, self.value)
+    }
+}
+
+impl From<WalEntry> for (Vec<u8>, Option<Vec<u8>>) {
+    fn from(entry: WalEntry) -> Self {
+        entry.into_kv()
+    }
+}
+
+/// First record in a WAL segment whose timestamp is lower than the one before
+/// it, as reported by `Wal::validate`.
+#[derive(Debug, PartialEq)]
+pub struct TimestampRegression {
+    pub index: usize,
+    pub previous: u128,
+    pub timestamp: u128,
+}
+
+/// Difference between a memtable and the WAL it was recovered from, as
+/// reported by `Wal::verify_replay`.
+#[derive(Debug, PartialEq)]
+pub enum ReplayMismatch {
+    /// Key is in the WAL but not in the memtable.
+    MissingFromMemTable(Vec<u8>),
+    /// Key is in the memtable but not in the WAL.
+    MissingFromWal(Vec<u8>),
+    /// Both have the key but disagree on value, timestamp or tombstone.
+    Differs(Vec<u8>),
+}
+
+/// Outcome of `Wal::salvage`.
+#[derive(Debug, PartialEq)]
+pub struct RecoveryReport {
+    /// Segment the salvaged records were written to.
+    pub path: PathBuf,
+    /// Number of records copied.
+    pub records: usize,
+    /// Length of the readable prefix of the source segment.
+    pub bytes_salvaged: u64,
+    /// Bytes after the readable prefix that were dropped.
+    pub bytes_dropped: u64,
+}
+
+/// How many times a failed WAL write is attempted before the error is
+/// returned, sleeping `backoff` before the first retry and doubling it after.
+/// The default makes a single attempt.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            backoff: Duration::from_millis(0),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Runs `op` until it succeeds or runs out of attempts.
+    fn run<T, F: FnMut() -> IoResult<T>>(&self, mut op: F) -> IoResult<T> {
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Err(_) if attempt < self.max_attempts => {
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+/// Writes all of `buff`, retrying the unwritten remainder on errors as per
+/// `policy`. Unlike retrying `write_all`, bytes written before a failure are
+/// never written twice.
+fn write_all_with_retry<W: Write>(writer: &mut W, mut buff: &[u8],
+                                  policy: &RetryPolicy) -> IoResult<()> {
+    while !buff.is_empty() {
+        let written = policy.run(|| match writer.write(buff) {
+            Ok(0) => Err(IoError::from(ErrorKind::WriteZero)),
+            Err(e) if e.kind() == ErrorKind::Interrupted => Ok(0),
+            res => res,
+        })?;
+        buff = &buff[written..];
+    }
+
+    Ok(())
+}
+
 pub struct Wal {
     path: PathBuf,
     file: BufWriter<File>,
+    format: SegmentFormat,
+    retry: RetryPolicy,
+    flush_each_write: bool,
+    stale_segments: Vec<PathBuf>,
 }
 
+/// Flushes the segment and reads it back from the start. Failing to flush
+/// or open it is returned as the first item.
 impl IntoIterator for Wal {
-    type Item = WalEntry;
-    type IntoIter = iterator::WalIterator;
+    type Item = IoResult<WalEntry>;
+    type IntoIter = iterator::WalRecords;
 
-    fn into_iter(self) -> Self::IntoIter {
-        iterator::WalIterator::new(self.path).unwrap()
+    fn into_iter(mut self) -> Self::IntoIter {
+        let res = self.flush()
+            .and_then(|_| iterator::WalIterator::new(self.path.clone()));
+        match res {
+            Ok(entries) => entries.records(),
+            Err(e) => iterator::WalRecords::failed(e),
+        }
     }
 }
 
 impl Wal {
 
     pub fn new(dir: &Path) -> IoResult<Wal> {
+        Self::with_encoding(dir, LengthEncoding::default())
+    }
+
+    pub fn with_encoding(dir: &Path,
+                         encoding: LengthEncoding) -> IoResult<Wal> {
         let timestamp = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_micros();
 
         let path = Path::new(dir).join(timestamp.to_string() + ".wal");
-        let file = OpenOptions::new().append(true).create(true).open(&path)?;
-        let file = BufWriter::new(file);
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        // Written straight to the file so that readers never find a segment
+        // without a header.
+        file.write_all(&segment_header(encoding))?;
+        utils::fsync_dir(dir)?;
+
+        Ok(Wal {
+            path,
+            file: BufWriter::new(file),
+            format: SegmentFormat::Framed(encoding),
+            retry: RetryPolicy::default(),
+            flush_each_write: false,
+            stale_segments: Vec::new(),
+        })
+    }
 
-        Ok(Wal { path, file })
+    /// Sets how failed appends and flushes are retried. Retries are off by
+    /// default.
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    /// Flushes the write buffer after every `set` and `delete` so other
+    /// handles see each record as soon as it is written. This doesn't fsync.
+    /// Off by default.
+    pub fn set_flush_each_write(&mut self, flush_each_write: bool) {
+        self.flush_each_write = flush_each_write;
     }
 
     pub fn set(&mut self, key: &[u8], value: &[u8],
                timestamp: u128) -> IoResult<()> {
+        let encoding = self.format.encoding();
+        let mut buff = Vec::with_capacity(key.len() + value.len() + 41);
+        buff.push(0);                                       // flags
+        buff.extend_from_slice(&timestamp.to_le_bytes());   // timestamp
+        encoding.encode(key.len(), &mut buff)?;             // k_size
+        buff.extend_from_slice(key);                        // key
+        encoding.encode(value.len(), &mut buff)?;           // v_size
+        buff.extend_from_slice(value);                      // value
 
-        self.file.write_all(&(false as u8).to_le_bytes())?; // tombstone
-        self.file.write_all(&timestamp.to_le_bytes())?;     // timestamp
-        self.file.write_all(&key.len().to_le_bytes())?;     // k_size
-        self.file.write_all(key)?;                          // key
-        self.file.write_all(&value.len().to_le_bytes())?;   // v_size
-        self.file.write_all(value)?;                        // value
-
-        Ok(())
+        self.write_record(buff)
     }
 
     pub fn delete(&mut self, key: &[u8], timestamp: u128) -> IoResult<()> {
-        self.file.write_all(&(true as u8).to_le_bytes())?;  // tombstone
-        self.file.write_all(&timestamp.to_le_bytes())?;     // timestamp
-        self.file.write_all(&key.len().to_le_bytes())?;     // k_size
-        self.file.write_all(key)?;                          // key
+        let encoding = self.format.encoding();
+        let mut buff = Vec::with_capacity(key.len() + 25);
+        buff.push(FLAG_DELETED);                            // flags
+        buff.extend_from_slice(&timestamp.to_le_bytes());   // timestamp
+        encoding.encode(key.len(), &mut buff)?;             // k_size
+        buff.extend_from_slice(key);                        // key
+
+        self.write_record(buff)
+    }
 
+    /// Writes an encoded entry, framing it unless the segment is headerless.
+    /// The frame is written in one go so a reader never sees its length
+    /// without a body.
+    fn write_record(&mut self, body: Vec<u8>) -> IoResult<()> {
+        if self.format == SegmentFormat::Headerless {
+            write_all_with_retry(&mut self.file, &body, &self.retry)?;
+            if self.flush_each_write {
+                self.flush()?;
+            }
+            return Ok(());
+        }
+        let body_size = u32::try_from(body.len()).map_err(|_| IoError::new(
+            ErrorKind::InvalidInput,
+            format!("WAL entry of {} bytes is too large", body.len()),
+        ))?;
+        let crc = utils::crc32(0, &body);
+        let mut frame = Vec::with_capacity(body.len() + 8);
+        frame.extend_from_slice(&body_size.to_le_bytes());  // body_size
+        frame.extend_from_slice(&body);                     // body
+        frame.extend_from_slice(&crc.to_le_bytes());        // crc
+
+        write_all_with_retry(&mut self.file, &frame, &self.retry)?;
+        if self.flush_each_write {
+            self.flush()?;
+        }
         Ok(())
     }
 
     pub fn flush(&mut self) -> IoResult<()> {
-        self.file.flush()
+        let file = &mut self.file;
+        self.retry.run(|| file.flush())
+    }
+
+    /// Flushes the write buffer and fsyncs the segment.
+    pub fn sync(&mut self) -> IoResult<()> {
+        self.flush()?;
+        self.file.get_ref().sync_all()
     }
 
+    /// Segments `load_from_dir` replayed into this WAL but failed to remove.
+    /// Their records are already in this WAL, so they can be deleted by hand.
+    pub fn stale_segments(&self) -> &[PathBuf] {
+        &self.stale_segments
+    }
+
+    /// Opens the segment at `path` for appending, creating it with the
+    /// default encoding if it doesn't exist. Appends to an existing segment
+    /// use the format from its header. Fails with `NotAWalFile` if the file
+    /// isn't a segment.
     pub fn from_path(path: &Path) -> IoResult<Wal> {
-        let file = OpenOptions::new().append(true).create(true).open(&path)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let format = if file.metadata()?.len() == 0 {
+            let encoding = LengthEncoding::default();
+            file.write_all(&segment_header(encoding))?;
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            utils::fsync_dir(dir)?;
+            SegmentFormat::Framed(encoding)
+        } else {
+            read_segment_header(&mut file, path)?.0
+        };
         let file = BufWriter::new(file);
 
         Ok(Wal {
             path: path.to_owned(),
             file,
+            format,
+            retry: RetryPolicy::default(),
+            flush_each_write: false,
+            stale_segments: Vec::new(),
+        })
+    }
+
+    /// Checks that timestamps in the segment at `path` never decrease and
+    /// returns the first record that breaks it. Segments produced by merging
+    /// may legitimately interleave, so this is never run on recovery. Fails
+    /// on a corrupt record.
+    pub fn validate(path: &Path) -> IoResult<Option<TimestampRegression>> {
+        let entries = iterator::WalIterator::new(path.to_owned())?.records();
+        let mut previous = None;
+        for (index, entry) in entries.enumerate() {
+            let entry = entry?;
+            if let Some(previous) = previous {
+                if entry.timestamp < previous {
+                    return Ok(Some(TimestampRegression {
+                        index,
+                        previous,
+                        timestamp: entry.timestamp,
+                    }));
+                }
+            }
+            previous = Some(entry.timestamp);
+        }
+
+        Ok(None)
+    }
+
+    /// Copies every readable record of the segment at `src` into a fresh
+    /// segment in `dst_dir`, stopping at the first record that is corrupt or
+    /// cut off. Other IO errors are returned. The copy uses the source's
+    /// length encoding, so unless the source predates the segment magic its
+    /// records are copied byte for byte. The source is left untouched. This
+    /// is a manual recovery tool, normal startup doesn't use it.
+    pub fn salvage(src: &Path, dst_dir: &Path) -> IoResult<RecoveryReport> {
+        let total = src.metadata()?.len();
+        let entries = iterator::WalIterator::new(src.to_owned())?;
+        let mut dst = Self::with_encoding(dst_dir, entries.encoding())?;
+        let mut entries = entries.records();
+        let mut records = 0;
+        let mut bytes_salvaged = entries.position();
+        while let Some(entry) = entries.next() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) if e.kind() == ErrorKind::InvalidData => break,
+                Err(e) => return Err(e),
+            };
+            match entry.value {
+                Some(value) => dst.set(&entry.key, &value, entry.timestamp)?,
+                None => dst.delete(&entry.key, entry.timestamp)?,
+            }
+            records += 1;
+            bytes_salvaged = entries.position();
+        }
+        dst.flush()?;
+
+        Ok(RecoveryReport {
+            path: dst.path,
+            records,
+            bytes_salvaged,
+            bytes_dropped: total - bytes_salvaged,
         })
     }
 
+    /// Iterates the records of all segments in `dir`, oldest segment first.
+    /// Unlike `load_from_dir` this leaves the segments untouched. A corrupt
+    /// record is returned as an error, see `WalRecords`.
+    pub fn iter_dir(dir: &Path)
+                    -> IoResult<impl Iterator<Item = IoResult<WalEntry>>> {
+        let mut wal_files = utils::get_files_by_ext(dir, "wal")?;
+        sort_segments(&mut wal_files);
+        let mut segments = Vec::with_capacity(wal_files.len());
+        for wal_file in wal_files {
+            // A segment that was created but never written to.
+            if wal_file.metadata()?.len() == 0 {
+                continue;
+            }
+            segments.push(iterator::WalIterator::new(wal_file)?.records());
+        }
+
+        Ok(segments.into_iter().flatten())
+    }
+
+    /// Returns every record in `dir` with a timestamp after `timestamp`,
+    /// tombstones included, ordered by timestamp and then log order. The
+    /// matching records are collected in memory to sort them.
+    pub fn changes_since(dir: &Path, timestamp: u128)
+                         -> IoResult<impl Iterator<Item = WalEntry>> {
+        let mut changes = Vec::new();
+        for entry in Self::iter_dir(dir)? {
+            let entry = entry?;
+            if entry.timestamp > timestamp {
+                changes.push(entry);
+            }
+        }
+        changes.sort_by_key(|entry| entry.timestamp);
+
+        Ok(changes.into_iter())
+    }
+
+    /// Replays all segments in `dir` and reports every key where the result
+    /// differs from `mem_table`. An empty result means the memtable is
+    /// exactly what the WAL recovers to. The segments are left untouched.
+    pub fn verify_replay(dir: &Path, mem_table: &memtable::MemTable)
+                         -> IoResult<Vec<ReplayMismatch>> {
+        let mut replayed = memtable::MemTable::new();
+        for entry in Self::iter_dir(dir)? {
+            let entry = entry?;
+            match entry.value {
+                Some(value) => replayed.set(&entry.key, &value, entry.timestamp),
+                None => replayed.delete(&entry.key, entry.timestamp),
+            }
+        }
+
+        let mut mismatches = Vec::new();
+        for entry in replayed.iter() {
+            match mem_table.get(&entry.key) {
+                None => mismatches.push(
+                    ReplayMismatch::MissingFromMemTable(entry.key.clone())),
+                Some(actual) if actual != entry => mismatches.push(
+                    ReplayMismatch::Differs(entry.key.clone())),
+                Some(_) => (),
+            }
+        }
+        for entry in mem_table.iter() {
+            if replayed.get(&entry.key).is_none() {
+                mismatches.push(ReplayMismatch::MissingFromWal(entry.key.clone()));
+            }
+        }
+
+        Ok(mismatches)
+    }
+
+    /// Replays all segments in `dir` into a new memtable and a new segment,
+    /// then removes the old segments. Records are applied in log order, so
+    /// for records with equal timestamps the one at the later segment and
+    /// offset wins (see `MemTable`). A record cut off at the end of a segment
+    /// by a crash is dropped. If a segment can't be read, because it isn't a
+    /// segment or has a corrupt record, the error is returned and every
+    /// segment is left in place.
     pub fn load_from_dir(dir: &Path) -> IoResult<(Wal, memtable::MemTable)> {
-        let mut wal_files = utils::get_files_by_ext(dir, "wal");
-        wal_files.sort();
+        let metadata = fs::metadata(dir).map_err(|e| match e.kind() {
+            ErrorKind::NotFound => IoError::new(
+                ErrorKind::NotFound,
+                format!("WAL directory {} does not exist", dir.display()),
+            ),
+            _ => e,
+        })?;
+        if !metadata.is_dir() {
+            return Err(IoError::new(
+                ErrorKind::NotADirectory,
+                format!("WAL directory {} is not a directory", dir.display()),
+            ));
+        }
+        let mut wal_files = utils::get_files_by_ext(dir, "wal")?;
+        sort_segments(&mut wal_files);
 
         let mut new_mem_table = memtable::MemTable::new();
         let mut new_wal = Self::new(dir)?;
         for wal_file in wal_files.iter() {
-            if let Ok(wal) = Self::from_path(wal_file) {
-                for entry in wal.into_iter() {
-                    if entry.deleted {
-                        new_mem_table.delete(entry.key.as_slice(), entry.timestamp);
-                        new_wal.delete(entry.key.as_slice(), entry.timestamp)?;
-                    } else {
-                        new_mem_table.set(
-                            entry.key.as_slice(),
-                            entry.value.as_ref().unwrap().as_slice(),
-                            entry.timestamp,
-                        );
-                        new_wal.set(
-                            entry.key.as_slice(),
-                            entry.value.unwrap().as_slice(),
-                            entry.timestamp,
-                        )?;
+            let metadata = wal_file.metadata()?;
+            // A segment that was created but never written to.
+            if !metadata.is_file() || metadata.len() == 0 {
+                continue;
+            }
+            if let Err(e) = new_wal.replay(wal_file, &mut new_mem_table) {
+                // The new segment only holds copies of records that are
+                // still in the old ones.
+                let path = new_wal.path.clone();
+                drop(new_wal);
+                remove_file(path)?;
+                return Err(e);
+            }
+        }
+
+        // The old segments may only go once their records are durable in the
+        // new one. Failing to remove one doesn't fail recovery.
+        new_wal.sync()?;
+        for wal_file in wal_files {
+            if remove_file(&wal_file).is_err() {
+                new_wal.stale_segments.push(wal_file);
+            }
+        }
+        utils::fsync_dir(dir)?;
+
+        Ok((new_wal, new_mem_table))
+    }
+
+    /// Appends the records of the segment at `path` to this WAL and applies
+    /// them to `mem_table`.
+    fn replay(&mut self, path: &Path,
+              mem_table: &mut memtable::MemTable) -> IoResult<()> {
+        for entry in iterator::WalIterator::new(path.to_owned())?.records() {
+            let entry = entry.map_err(|e| IoError::new(
+                e.kind(),
+                format!("{}: {}", path.display(), e),
+            ))?;
+            match entry.value {
+                Some(value) => {
+                    mem_table.set(&entry.key, &value, entry.timestamp);
+                    self.set(&entry.key, &value, entry.timestamp)?;
+                }
+                None => {
+                    mem_table.delete(&entry.key, entry.timestamp);
+                    self.delete(&entry.key, entry.timestamp)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+}
+
+/// Sorts WAL segments chronologically by the numeric timestamp in their
+/// names. A plain lexicographic sort would put `1000000.wal` before
+/// `999999.wal`.
+fn sort_segments(files: &mut [PathBuf]) {
+    files.sort_by_key(|path| {
+        path.file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<u128>().ok())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils;
+    use crate::wal::{
+        write_all_with_retry, LengthEncoding, RecoveryReport, ReplayMismatch,
+        RetryPolicy,
+        NotAWalFile, TimestampRegression, TruncatedRecord, Wal, WalIterator,
+        FLAG_DELETED, SEGMENT_MAGIC, SEGMENT_VERSION,
+    };
+    use crate::memtable::MemTable;
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+    use std::io::{self, ErrorKind, Write};
+    use std::time::Duration;
+    use std::fs;
+    use std::path::Path;
+
+    /// Fails the first `failures` writes, then accepts up to 3 bytes per
+    /// write.
+    struct FlakyWriter {
+        failures: u32,
+        data: Vec<u8>,
+    }
+
+    impl Write for FlakyWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::Error::other("flaky"));
+            }
+            let n = buf.len().min(3);
+            self.data.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Appends a framed record with `Fixed64` lengths, writing the segment
+    /// header first if `path` doesn't exist yet.
+    fn write_raw_record(path: &Path, flags: u8, timestamp: u128, key: &[u8],
+                        value: Option<&[u8]>) {
+        let mut buff = fs::read(path).unwrap_or_default();
+        if buff.is_empty() {
+            buff.extend_from_slice(&SEGMENT_MAGIC);
+            buff.push(SEGMENT_VERSION);
+            buff.push(LengthEncoding::Fixed64.id());
+        }
+        let mut body = vec![flags];
+        body.extend_from_slice(&timestamp.to_le_bytes());
+        body.extend_from_slice(&key.len().to_le_bytes());
+        body.extend_from_slice(key);
+        if let Some(value) = value {
+            body.extend_from_slice(&value.len().to_le_bytes());
+            body.extend_from_slice(value);
+        }
+        buff.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        buff.extend_from_slice(&body);
+        buff.extend_from_slice(&utils::crc32(0, &body).to_le_bytes());
+        fs::write(path, buff).unwrap();
+    }
+
+    /// Appends an unframed record with `Fixed64` lengths and no segment
+    /// header, as written before the magic existed.
+    fn write_legacy_record(path: &Path, flags: u8, timestamp: u128,
+                           key: &[u8], value: Option<&[u8]>) {
+        let mut buff = fs::read(path).unwrap_or_default();
+        buff.push(flags);
+        buff.extend_from_slice(&timestamp.to_le_bytes());
+        buff.extend_from_slice(&key.len().to_le_bytes());
+        buff.extend_from_slice(key);
+        if let Some(value) = value {
+            buff.extend_from_slice(&value.len().to_le_bytes());
+            buff.extend_from_slice(value);
+        }
+        fs::write(path, buff).unwrap();
+    }
+
+    #[test]
+    fn test_wal_new_fsyncs_dir() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let wal = Wal::new(dir.path()).unwrap();
+
+        assert!(wal.path.exists());
+        assert_eq!(utils::synced_dirs(), vec![dir.path().to_owned()]);
+    }
+
+    #[test]
+    fn test_wal_from_path_fsyncs_dir_on_create() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.wal");
+
+        Wal::from_path(&path).unwrap();
+        Wal::from_path(&path).unwrap();
+
+        assert_eq!(utils::synced_dirs(), vec![dir.path().to_owned()]);
+    }
+
+    #[test]
+    fn test_wal_load_from_dir_numeric_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::from_path(&dir.path().join("999999.wal")).unwrap();
+        wal.set(b"key", b"old value", 999999).unwrap();
+        wal.flush().unwrap();
+        let mut wal = Wal::from_path(&dir.path().join("1000000.wal")).unwrap();
+        wal.set(b"key", b"new value", 1000000).unwrap();
+        wal.flush().unwrap();
+
+        let (_, mem_table) = Wal::load_from_dir(dir.path()).unwrap();
+
+        let entry = mem_table.get(b"key").unwrap();
+        assert_eq!(entry.value().unwrap(), b"new value");
+        assert_eq!(entry.timestamp, 1000000);
+    }
+
+    #[test]
+    fn test_wal_validate_monotonic() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::new(dir.path()).unwrap();
+        wal.set(b"key 1", b"value", 1).unwrap();
+        wal.delete(b"key 1", 1).unwrap();
+        wal.set(b"key 2", b"value", 2).unwrap();
+        wal.flush().unwrap();
+
+        let res = Wal::validate(&wal.path).unwrap();
+
+        assert_eq!(res, None);
+    }
+
+    #[test]
+    fn test_wal_validate_timestamp_regression() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::new(dir.path()).unwrap();
+        wal.set(b"key 1", b"value", 1).unwrap();
+        wal.set(b"key 2", b"value", 3).unwrap();
+        wal.delete(b"key 1", 2).unwrap();
+        wal.set(b"key 3", b"value", 0).unwrap();
+        wal.flush().unwrap();
+
+        let res = Wal::validate(&wal.path).unwrap();
+
+        assert_eq!(res, Some(TimestampRegression {
+            index: 2,
+            previous: 3,
+            timestamp: 2,
+        }));
+    }
+
+    #[test]
+    fn test_wal_binary_keys_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys: [&[u8]; 6] = [b"abc", b"\xff", b"a\0c", b"", b"ab", b"\n"];
+        let mut wal = Wal::new(dir.path()).unwrap();
+        for (i, key) in keys.iter().enumerate() {
+            wal.set(key, &[0, 0xff, b'\n', 0x80], i as u128).unwrap();
+        }
+        wal.delete(b"ab\0", 6).unwrap();
+        wal.flush().unwrap();
+
+        let (_, mem_table) = Wal::load_from_dir(dir.path()).unwrap();
+
+        assert_eq!(mem_table.len(), 7);
+        for key in keys.iter() {
+            let entry = mem_table.get(key).unwrap();
+            assert_eq!(entry.key, *key);
+            assert_eq!(entry.value().unwrap(), &[0, 0xff, b'\n', 0x80]);
+        }
+        assert!(mem_table.get(b"ab\0").unwrap().deleted);
+    }
+
+    #[test]
+    fn test_wal_flags_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::new(dir.path()).unwrap();
+        wal.set(b"key 1", b"value", 0).unwrap();
+        wal.delete(b"key 2", 1).unwrap();
+        wal.flush().unwrap();
+        let path = wal.path.clone();
+
+        let data = fs::read(&path).unwrap();
+        let entries = wal.into_iter().collect::<io::Result<Vec<_>>>().unwrap();
+
+        assert_eq!(&data[..4], &SEGMENT_MAGIC);
+        assert_eq!(data[4], SEGMENT_VERSION);
+        assert_eq!(data[6 + 4], 0);
+        assert_eq!(data[6 + 4 + 43 + 4 + 4], FLAG_DELETED);
+        assert_eq!(entries.len(), 2);
+        assert!(!entries[0].deleted);
+        assert_eq!(entries[0].value.as_deref().unwrap(), b"value");
+        assert!(entries[1].deleted);
+        assert_eq!(entries[1].value, None);
+    }
+
+    #[test]
+    fn test_wal_legacy_tombstone_byte() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.wal");
+        write_legacy_record(&path, false as u8, 0, b"key 1", Some(b"value"));
+        write_legacy_record(&path, true as u8, 1, b"key 2", None);
+
+        let entries = Wal::from_path(&path).unwrap()
+            .into_iter()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].key, b"key 1");
+        assert!(!entries[0].deleted);
+        assert_eq!(entries[0].value.as_deref().unwrap(), b"value");
+        assert_eq!(entries[1].key, b"key 2");
+        assert!(entries[1].deleted);
+    }
+
+    #[test]
+    fn test_wal_reserved_flags_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.wal");
+        write_raw_record(&path, 0, 0, b"key 1", Some(b"value"));
+        write_raw_record(&path, 0b10, 1, b"key 2", Some(b"value"));
+
+        let entries: Vec<_> = Wal::from_path(&path).unwrap()
+            .into_iter()
+            .collect();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].as_ref().unwrap().key, b"key 1");
+        let err = entries[1].as_ref().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_wal_salvage() {
+        let src_dir = tempfile::tempdir().unwrap();
+        let dst_dir = tempfile::tempdir().unwrap();
+        let src = src_dir.path().join("1.wal");
+        write_raw_record(&src, 0, 0, b"key 1", Some(b"value"));
+        write_raw_record(&src, FLAG_DELETED, 1, b"key 2", None);
+        let prefix_len = fs::read(&src).unwrap().len() as u64;
+        write_raw_record(&src, 0xff, 2, b"key 3", Some(b"value"));
+        write_raw_record(&src, 0, 3, b"key 4", Some(b"value"));
+        let total_len = fs::read(&src).unwrap().len() as u64;
+
+        let report = Wal::salvage(&src, dst_dir.path()).unwrap();
+
+        assert_eq!(report, RecoveryReport {
+            path: report.path.clone(),
+            records: 2,
+            bytes_salvaged: prefix_len,
+            bytes_dropped: total_len - prefix_len,
+        });
+        assert!(report.path.starts_with(dst_dir.path()));
+        let salvaged_len = fs::read(&report.path).unwrap().len() as u64;
+        assert_eq!(salvaged_len, prefix_len);
+        let entries = Wal::from_path(&report.path).unwrap()
+            .into_iter()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].key, b"key 1");
+        assert_eq!(entries[0].value.as_deref().unwrap(), b"value");
+        assert_eq!(entries[1].key, b"key 2");
+        assert!(entries[1].deleted);
+        assert_eq!(fs::read(&src).unwrap().len() as u64, total_len);
+    }
+
+    #[test]
+    fn test_wal_salvage_keeps_encoding() {
+        let src_dir = tempfile::tempdir().unwrap();
+        let dst_dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::with_encoding(src_dir.path(), LengthEncoding::Varint)
+            .unwrap();
+        wal.set(b"key 1", b"value", 0).unwrap();
+        wal.delete(b"key 2", 1).unwrap();
+        wal.flush().unwrap();
+        let src = wal.path.clone();
+        let mut data = fs::read(&src).unwrap();
+        let prefix_len = data.len() as u64;
+        // A frame whose checksum doesn't match its body.
+        data.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 0]);
+        fs::write(&src, &data).unwrap();
+
+        let report = Wal::salvage(&src, dst_dir.path()).unwrap();
+
+        assert_eq!(report.records, 2);
+        assert_eq!(report.bytes_salvaged, prefix_len);
+        assert_eq!(report.bytes_dropped, 9);
+        let salvaged = fs::read(&report.path).unwrap();
+        assert_eq!(salvaged[..], data[..prefix_len as usize]);
+        assert_eq!(salvaged[5], LengthEncoding::Varint.id());
+    }
+
+    #[test]
+    fn test_wal_truncated_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.wal");
+        write_raw_record(&path, 0, 0, b"key 1", Some(b"value"));
+        let offset = fs::read(&path).unwrap().len() as u64;
+        write_raw_record(&path, 0, 1, b"key 2", Some(b"value"));
+        let mut data = fs::read(&path).unwrap();
+        let body_size_pos = offset as usize;
+        data[body_size_pos..body_size_pos + 4]
+            .copy_from_slice(&100u32.to_le_bytes());
+        fs::write(&path, data).unwrap();
+
+        let mut iter = WalIterator::new(path).unwrap();
+
+        assert_eq!(iter.read_entry().unwrap().unwrap().key, b"key 1");
+        let err = iter.read_entry().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        let truncated = err.get_ref().unwrap()
+            .downcast_ref::<TruncatedRecord>()
+            .unwrap();
+        assert_eq!(truncated, &TruncatedRecord {
+            offset,
+            claimed: 104,
+            remaining: 43 + 4,
+        });
+    }
+
+    #[test]
+    fn test_wal_clean_eof() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.wal");
+        write_raw_record(&path, 0, 0, b"key 1", Some(b"value"));
+
+        let mut iter = WalIterator::new(path).unwrap();
+
+        assert_eq!(iter.read_entry().unwrap().unwrap().key, b"key 1");
+        assert!(iter.read_entry().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_wal_load_from_dir_same_timestamp() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::from_path(&dir.path().join("1.wal")).unwrap();
+        wal.set(b"key 1", b"value 1", 5).unwrap();
+        wal.set(b"key 2", b"value", 5).unwrap();
+        wal.flush().unwrap();
+        let mut wal = Wal::from_path(&dir.path().join("2.wal")).unwrap();
+        wal.set(b"key 1", b"value 2", 5).unwrap();
+        wal.delete(b"key 2", 5).unwrap();
+        wal.set(b"key 2", b"value 2", 5).unwrap();
+        wal.delete(b"key 2", 5).unwrap();
+        wal.flush().unwrap();
+
+        let (_, mem_table) = Wal::load_from_dir(dir.path()).unwrap();
+
+        assert_eq!(mem_table.get(b"key 1").unwrap().value().unwrap(),
+                   b"value 2");
+        assert!(mem_table.get(b"key 2").unwrap().deleted);
+    }
+
+    #[test]
+    fn test_wal_load_from_missing_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+
+        let err = Wal::load_from_dir(&missing).err().unwrap();
+
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(err.to_string().contains("missing"));
+        assert!(!missing.exists());
+    }
+
+    #[test]
+    fn test_wal_load_from_dir_not_a_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, b"").unwrap();
+
+        let err = Wal::load_from_dir(&file).err().unwrap();
+
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_wal_load_from_dir_ignores_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("LOCK"), b"").unwrap();
+        fs::create_dir(dir.path().join("sst")).unwrap();
+        let mut wal = Wal::new(dir.path()).unwrap();
+        wal.set(b"key 1", b"value", 0).unwrap();
+        wal.flush().unwrap();
+
+        let (_, mem_table) = Wal::load_from_dir(dir.path()).unwrap();
+
+        assert_eq!(mem_table.len(), 1);
+        assert!(dir.path().join("LOCK").exists());
+    }
+
+    #[test]
+    fn test_wal_length_encodings() {
+        let encodings = [
+            (LengthEncoding::Fixed64, 8),
+            (LengthEncoding::Fixed32, 4),
+            (LengthEncoding::Varint, 1),
+        ];
+        let large_value = vec![1; 300];
+        for &(encoding, size_len) in encodings.iter() {
+            let dir = tempfile::tempdir().unwrap();
+            let mut wal = Wal::with_encoding(dir.path(), encoding).unwrap();
+            wal.set(b"key 1", b"value", 0).unwrap();
+            wal.set(b"key 2", &large_value, 1).unwrap();
+            wal.delete(b"key 3", 2).unwrap();
+            wal.flush().unwrap();
+            let path = wal.path.clone();
+
+            let data = fs::read(&path).unwrap();
+            let entries = wal.into_iter().collect::<io::Result<Vec<_>>>().unwrap();
+
+            // A 300 byte value takes a second varint byte.
+            let extra = if encoding == LengthEncoding::Varint { 1 } else { 0 };
+            assert_eq!(data.len(), 6
+                + 8 + 1 + 16 + size_len + 5 + size_len + 5
+                + 8 + 1 + 16 + size_len + 5 + size_len + extra + 300
+                + 8 + 1 + 16 + size_len + 5);
+            assert_eq!(entries.len(), 3);
+            assert_eq!(entries[0].key, b"key 1");
+            assert_eq!(entries[0].value.as_deref().unwrap(), b"value");
+            assert_eq!(entries[1].key, b"key 2");
+            assert_eq!(entries[1].value.as_ref().unwrap(), &large_value);
+            assert_eq!(entries[2].key, b"key 3");
+            assert!(entries[2].deleted);
+        }
+    }
+
+    #[test]
+    fn test_wal_from_path_keeps_encoding() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::with_encoding(dir.path(), LengthEncoding::Varint)
+            .unwrap();
+        wal.set(b"key 1", b"value", 0).unwrap();
+        wal.flush().unwrap();
+        let path = wal.path.clone();
+
+        let mut wal = Wal::from_path(&path).unwrap();
+        wal.set(b"key 2", b"value", 1).unwrap();
+        wal.flush().unwrap();
+
+        let entries = wal.into_iter().collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].key, b"key 2");
+        assert_eq!(entries[1].value.as_deref().unwrap(), b"value");
+    }
+
+    #[test]
+    fn test_wal_load_from_dir_skips_empty_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let empty = dir.path().join("1.wal");
+        fs::write(&empty, b"").unwrap();
+        let mut wal = Wal::from_path(&dir.path().join("2.wal")).unwrap();
+        wal.set(b"key 1", b"value", 0).unwrap();
+        wal.flush().unwrap();
+
+        let (new_wal, mem_table) = Wal::load_from_dir(dir.path()).unwrap();
+
+        assert_eq!(mem_table.len(), 1);
+        assert!(!empty.exists());
+        let files = utils::get_files_by_ext(dir.path(), "wal").unwrap();
+        assert_eq!(files, vec![new_wal.path.clone()]);
+    }
+
+    #[test]
+    fn test_wal_write_retry() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(1),
+        };
+        let mut writer = FlakyWriter { failures: 2, data: Vec::new() };
+
+        write_all_with_retry(&mut writer, b"some record", &policy).unwrap();
+
+        assert_eq!(writer.data, b"some record");
+    }
+
+    #[test]
+    fn test_wal_write_retry_exhausted() {
+        let mut writer = FlakyWriter { failures: 2, data: Vec::new() };
+
+        let res = write_all_with_retry(&mut writer, b"some record",
+                                       &RetryPolicy::default());
+
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::Other);
+        assert!(writer.data.is_empty());
+    }
+
+    #[test]
+    fn test_wal_set_with_retry_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::new(dir.path()).unwrap();
+        wal.set_retry_policy(RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(1),
+        });
+
+        wal.set(b"key 1", b"value", 0).unwrap();
+        wal.delete(b"key 1", 1).unwrap();
+        wal.flush().unwrap();
+
+        let entries = wal.into_iter().collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(entries.len(), 2);
+    }
+
+    #[test]
+    fn test_wal_iter_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let names = ["1000000.wal", "999999.wal", "1000001.wal"];
+        for (i, name) in names.iter().enumerate() {
+            let mut wal = Wal::from_path(&dir.path().join(name)).unwrap();
+            wal.set(name.as_bytes(), b"value", i as u128).unwrap();
+            wal.delete(name.as_bytes(), i as u128).unwrap();
+            wal.flush().unwrap();
+        }
+
+        let entries = Wal::iter_dir(dir.path()).unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+
+        let keys: Vec<_> = entries.iter().map(|e| e.key.as_slice()).collect();
+        assert_eq!(keys, vec![
+            &b"999999.wal"[..], b"999999.wal",
+            b"1000000.wal", b"1000000.wal",
+            b"1000001.wal", b"1000001.wal",
+        ]);
+        assert!(!entries[0].deleted);
+        assert!(entries[1].deleted);
+        for name in names.iter() {
+            assert!(dir.path().join(name).exists());
+        }
+    }
+
+    #[test]
+    fn test_wal_framed_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::new(dir.path()).unwrap();
+        wal.set(b"key 1", b"value", 0).unwrap();
+        wal.delete(b"key 2", 1).unwrap();
+        wal.flush().unwrap();
+        let complete_len = fs::read(&wal.path).unwrap().len() as u64;
+        wal.set(b"key 3", b"value", 2).unwrap();
+        wal.flush().unwrap();
+        let path = wal.path.clone();
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() - 6]).unwrap();
+
+        let mut iter = WalIterator::new(path.clone()).unwrap();
+
+        assert_eq!(iter.read_entry().unwrap().unwrap().key, b"key 1");
+        assert_eq!(iter.read_entry().unwrap().unwrap().key, b"key 2");
+        let err = iter.read_entry().unwrap_err();
+        let truncated = err.get_ref().unwrap()
+            .downcast_ref::<TruncatedRecord>()
+            .unwrap();
+        assert_eq!(truncated.offset, complete_len);
+        assert_eq!(iter.position(), complete_len);
+        let entries: Vec<_> = WalIterator::new(path).unwrap().collect();
+        assert_eq!(entries.len(), 2);
+    }
+
+    #[test]
+    fn test_wal_framed_checksum_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::new(dir.path()).unwrap();
+        wal.set(b"key 1", b"value", 0).unwrap();
+        wal.set(b"key 2", b"value", 1).unwrap();
+        wal.flush().unwrap();
+        let path = wal.path.clone();
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 5;
+        data[last] ^= 1;
+        fs::write(&path, data).unwrap();
+
+        let mut iter = WalIterator::new(path).unwrap();
+
+        assert_eq!(iter.read_entry().unwrap().unwrap().key, b"key 1");
+        let err = iter.read_entry().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("checksum"));
+    }
+
+    #[test]
+    fn test_wal_headerless_segment_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.wal");
+        write_legacy_record(&path, 0, 0, b"key 1", Some(b"value"));
+
+        let mut wal = Wal::from_path(&path).unwrap();
+        wal.delete(b"key 1", 1).unwrap();
+        wal.flush().unwrap();
+
+        let entries = wal.into_iter().collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].value.as_deref().unwrap(), b"value");
+        assert!(entries[1].deleted);
+    }
+
+    #[test]
+    fn test_wal_verify_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::new(dir.path()).unwrap();
+        wal.set(b"key 1", b"value", 0).unwrap();
+        wal.set(b"key 2", b"value", 1).unwrap();
+        wal.delete(b"key 3", 2).unwrap();
+        wal.flush().unwrap();
+        let (mut wal, mut mem_table) = Wal::load_from_dir(dir.path()).unwrap();
+
+        assert_eq!(Wal::verify_replay(dir.path(), &mem_table).unwrap(), vec![]);
+
+        wal.set(b"key 1", b"new value", 3).unwrap();
+        wal.set(b"key 4", b"value", 4).unwrap();
+        wal.flush().unwrap();
+        mem_table.delete(b"key 2", 5);
+        mem_table.set(b"key 5", b"value", 6);
+
+        assert_eq!(Wal::verify_replay(dir.path(), &mem_table).unwrap(), vec![
+            ReplayMismatch::Differs(b"key 1".to_vec()),
+            ReplayMismatch::Differs(b"key 2".to_vec()),
+            ReplayMismatch::MissingFromMemTable(b"key 4".to_vec()),
+            ReplayMismatch::MissingFromWal(b"key 5".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn test_wal_validate_compares_decoded_timestamps() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::new(dir.path()).unwrap();
+        wal.set(b"key 1", b"value", 255).unwrap();
+        wal.set(b"key 2", b"value", 256).unwrap();
+        wal.set(b"key 3", b"value", 1 << 64).unwrap();
+        wal.set(b"key 4", b"value", (1 << 64) - 1).unwrap();
+        wal.flush().unwrap();
+
+        let res = Wal::validate(&wal.path).unwrap();
+
+        assert_eq!(res, Some(TimestampRegression {
+            index: 3,
+            previous: 1 << 64,
+            timestamp: (1 << 64) - 1,
+        }));
+    }
+
+    #[test]
+    fn test_wal_changes_since() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::from_path(&dir.path().join("1.wal")).unwrap();
+        wal.set(b"key 1", b"value", 10).unwrap();
+        wal.set(b"key 2", b"value", 30).unwrap();
+        wal.flush().unwrap();
+        let mut wal = Wal::from_path(&dir.path().join("2.wal")).unwrap();
+        wal.set(b"key 3", b"value", 25).unwrap();
+        wal.delete(b"key 1", 20).unwrap();
+        wal.set(b"key 4", b"value", 40).unwrap();
+        wal.flush().unwrap();
+
+        let changes: Vec<_> = Wal::changes_since(dir.path(), 10).unwrap()
+            .map(|e| (e.key, e.timestamp, e.deleted))
+            .collect();
+
+        assert_eq!(changes, vec![
+            (b"key 1".to_vec(), 20, true),
+            (b"key 3".to_vec(), 25, false),
+            (b"key 2".to_vec(), 30, false),
+            (b"key 4".to_vec(), 40, false),
+        ]);
+    }
+
+    #[test]
+    fn test_wal_magic_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::new(dir.path()).unwrap();
+        wal.set(b"key 1", b"value", 0).unwrap();
+        wal.flush().unwrap();
+        let path = wal.path.clone();
+
+        let mut wal = Wal::from_path(&path).unwrap();
+        wal.set(b"key 2", b"value", 1).unwrap();
+        wal.flush().unwrap();
+
+        let data = fs::read(&path).unwrap();
+        assert_eq!(&data[..4], &SEGMENT_MAGIC);
+        let entries: Vec<_> = WalIterator::new(path).unwrap().collect();
+        assert_eq!(entries.len(), 2);
+    }
+
+    #[test]
+    fn test_wal_not_a_wal_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let junk = dir.path().join("1.wal");
+        let short = dir.path().join("2.wal");
+        fs::write(&junk, b"not a write-ahead log").unwrap();
+        fs::write(&short, b"RW").unwrap();
+        // Looked like a header before the magic, never written by a release.
+        let high_bit = dir.path().join("3.wal");
+        fs::write(&high_bit, [0xc0, 0, 0]).unwrap();
+
+        for path in [&junk, &short, &high_bit].iter() {
+            let err = WalIterator::new(path.to_path_buf()).err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            let not_a_wal = err.get_ref().unwrap()
+                .downcast_ref::<NotAWalFile>()
+                .unwrap();
+            assert_eq!(not_a_wal.path, **path);
+            let err = Wal::from_path(path).err().unwrap();
+            assert!(err.get_ref().unwrap().is::<NotAWalFile>());
+        }
+        assert_eq!(fs::read(&junk).unwrap(), b"not a write-ahead log");
+    }
+
+    #[test]
+    fn test_wal_tail_live_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::new(dir.path()).unwrap();
+        wal.set(b"key 1", b"value", 0).unwrap();
+        wal.flush().unwrap();
+
+        let mut tail = WalIterator::new(wal.path.clone()).unwrap();
+        assert_eq!(tail.next().unwrap().key, b"key 1");
+        assert!(tail.next().is_none());
+
+        wal.set(b"key 2", b"value", 1).unwrap();
+        wal.flush().unwrap();
+        assert!(tail.next().is_none());
+        tail.resume().unwrap();
+        assert_eq!(tail.next().unwrap().key, b"key 2");
+        assert!(tail.next().is_none());
+    }
+
+    #[test]
+    fn test_wal_tail_segment_shrank() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.wal");
+        write_raw_record(&path, 0, 0, b"key 1", Some(b"value"));
+        write_raw_record(&path, 0, 1, b"key 2", Some(b"value"));
+        let mut tail = WalIterator::new(path.clone()).unwrap();
+        assert_eq!(tail.by_ref().count(), 2);
+
+        fs::write(&path, &fs::read(&path).unwrap()[..3]).unwrap();
+
+        let err = tail.resume().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_wal_tail_partial_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.wal");
+        write_raw_record(&path, 0, 0, b"key 1", Some(b"value"));
+        let offset = fs::read(&path).unwrap().len();
+        write_raw_record(&path, 0, 1, b"key 2", Some(b"value"));
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..offset + 10]).unwrap();
+
+        let mut tail = WalIterator::new(path.clone()).unwrap();
+        assert_eq!(tail.next().unwrap().key, b"key 1");
+        assert!(tail.next().is_none());
+        tail.resume().unwrap();
+        assert!(tail.next().is_none());
+        assert_eq!(tail.position(), offset as u64);
+
+        fs::write(&path, &data).unwrap();
+        tail.resume().unwrap();
+        assert_eq!(tail.next().unwrap().key, b"key 2");
+        assert!(tail.next().is_none());
+    }
+
+    #[test]
+    fn test_wal_flush_each_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::new(dir.path()).unwrap();
+        wal.flush().unwrap();
+        let mut reader = WalIterator::new(wal.path.clone()).unwrap();
+
+        wal.set(b"key 1", b"value", 0).unwrap();
+        reader.resume().unwrap();
+        assert!(reader.next().is_none());
+
+        wal.set_flush_each_write(true);
+        wal.set(b"key 2", b"value", 1).unwrap();
+        reader.resume().unwrap();
+        assert_eq!(reader.next().unwrap().key, b"key 1");
+        assert_eq!(reader.next().unwrap().key, b"key 2");
+        wal.delete(b"key 1", 2).unwrap();
+        reader.resume().unwrap();
+        assert!(reader.next().unwrap().deleted);
+    }
+
+    #[test]
+    fn test_wal_tombstone_has_no_value() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::new(dir.path()).unwrap();
+        wal.delete(b"key 1", 7).unwrap();
+        wal.flush().unwrap();
+
+        let data = fs::read(&wal.path).unwrap();
+        let frame = &data[6..];
+        assert_eq!(frame.len(), 4 + 1 + 16 + 8 + 5 + 4);
+        assert_eq!(&frame[..4], &(1u32 + 16 + 8 + 5).to_le_bytes());
+        let body = &frame[4..frame.len() - 4];
+        assert_eq!(body[0], FLAG_DELETED);
+        assert_eq!(&body[1..17], &7u128.to_le_bytes());
+        assert_eq!(&body[17..25], &5usize.to_le_bytes());
+        assert_eq!(&body[25..], b"key 1");
+        assert_eq!(&frame[frame.len() - 4..],
+                   &utils::crc32(0, body).to_le_bytes());
+    }
+
+    #[test]
+    fn test_wal_tombstone_with_value_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.wal");
+        write_raw_record(&path, 0, 0, b"key 1", Some(b"value"));
+        write_raw_record(&path, FLAG_DELETED, 1, b"key 1", Some(b"value"));
+
+        let mut iter = WalIterator::new(path).unwrap();
+
+        assert_eq!(iter.read_entry().unwrap().unwrap().key, b"key 1");
+        let err = iter.read_entry().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("tombstone carries a value"));
+    }
+
+    #[test]
+    fn test_wal_load_from_dir_removal_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::from_path(&dir.path().join("1.wal")).unwrap();
+        wal.set(b"key 1", b"value", 0).unwrap();
+        wal.flush().unwrap();
+        // A directory can't be removed with remove_file.
+        let stuck = dir.path().join("2.wal");
+        fs::create_dir(&stuck).unwrap();
+
+        let (wal, mem_table) = Wal::load_from_dir(dir.path()).unwrap();
+
+        assert_eq!(mem_table.len(), 1);
+        assert_eq!(wal.stale_segments().to_vec(), vec![stuck.clone()]);
+        assert!(!dir.path().join("1.wal").exists());
+        assert!(stuck.exists());
+        let entries = wal.into_iter().collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(entries.len(), 1);
+    }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Set(Vec<u8>, Vec<u8>),
+        Delete(Vec<u8>),
+    }
+
+    /// Short keys over a few byte values so that operations often hit the
+    /// same key. Keys and values may be empty.
+    fn op() -> impl Strategy<Value = Op> {
+        let key = prop::collection::vec(0u8..4, 0..3);
+        let value = prop::collection::vec(any::<u8>(), 0..40);
+        prop_oneof![
+            3 => (key.clone(), value).prop_map(|(k, v)| Op::Set(k, v)),
+            1 => key.prop_map(Op::Delete),
+        ]
+    }
+
+    fn encoding() -> impl Strategy<Value = LengthEncoding> {
+        prop_oneof![
+            Just(LengthEncoding::Fixed64),
+            Just(LengthEncoding::Fixed32),
+            Just(LengthEncoding::Varint),
+        ]
+    }
+
+    // Failing cases are shrunk and saved under proptest-regressions/, and
+    // are replayed first on later runs.
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn test_wal_round_trip_matches_oracle(
+            ops in prop::collection::vec(op(), 0..100),
+            encoding in encoding(),
+        ) {
+            let dir = tempfile::tempdir().unwrap();
+            let mut wal = Wal::with_encoding(dir.path(), encoding).unwrap();
+            let mut mem_table = MemTable::new();
+            let mut oracle = BTreeMap::new();
+            for (timestamp, op) in ops.iter().enumerate() {
+                let timestamp = timestamp as u128;
+                match op {
+                    Op::Set(key, value) => {
+                        wal.set(key, value, timestamp).unwrap();
+                        mem_table.set(key, value, timestamp);
+                        oracle.insert(key.clone(), Some(value.clone()));
+                    }
+                    Op::Delete(key) => {
+                        wal.delete(key, timestamp).unwrap();
+                        mem_table.delete(key, timestamp);
+                        oracle.insert(key.clone(), None);
                     }
                 }
             }
+            wal.flush().unwrap();
+            drop(wal);
+
+            let (_, recovered) = Wal::load_from_dir(dir.path()).unwrap();
+
+            // Size accounting mustn't depend on how a key got to its state.
+            let mut expected_size = MemTable::new();
+            for (key, value) in oracle.iter() {
+                match value {
+                    Some(value) => expected_size.set(key, value, 0),
+                    None => expected_size.delete(key, 0),
+                }
+            }
+            for table in [&mem_table, &recovered].iter() {
+                prop_assert_eq!(table.len(), oracle.len());
+                prop_assert_eq!(table.size(), expected_size.size());
+                for (key, value) in oracle.iter() {
+                    prop_assert_eq!(table.get_value(key), value.as_deref());
+                }
+            }
+            prop_assert_eq!(
+                recovered.iter().collect::<Vec<_>>(),
+                mem_table.iter().collect::<Vec<_>>()
+            );
         }
+    }
 
-        new_wal.flush().unwrap();
-        wal_files.into_iter().for_each(|f| remove_file(f).unwrap());
+    #[test]
+    fn test_wal_entry_into_kv() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::new(dir.path()).unwrap();
+        wal.set(b"key 1", b"value", 0).unwrap();
+        wal.delete(b"key 2", 1).unwrap();
+        wal.flush().unwrap();
 
-        Ok((new_wal, new_mem_table))
+        let mut entries = wal.into_iter();
+        let set = entries.next().unwrap().unwrap();
+        let tombstone = entries.next().unwrap().unwrap();
+
+        assert!(!set.is_tombstone());
+        assert!(tombstone.is_tombstone());
+        assert_eq!(set.into_kv(), (b"key 1".to_vec(), Some(b"value".to_vec())));
+        let (key, value): (Vec<u8>, Option<Vec<u8>>) = tombstone.into();
+        assert_eq!(key, b"key 2");
+        assert_eq!(value, None);
+    }
+
+    /// Flips a byte in the key of the second of three records.
+    fn write_corrupt_segment(path: &Path) {
+        write_raw_record(path, 0, 0, b"key 1", Some(b"value"));
+        let offset = fs::read(path).unwrap().len();
+        write_raw_record(path, 0, 1, b"key 2", Some(b"value"));
+        write_raw_record(path, 0, 2, b"key 3", Some(b"value"));
+        let mut data = fs::read(path).unwrap();
+        data[offset + 4 + 1 + 16 + 8] ^= 1;
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn test_wal_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let corrupt = dir.path().join("1.wal");
+        write_corrupt_segment(&corrupt);
+        let truncated = dir.path().join("2.wal");
+        write_raw_record(&truncated, 0, 0, b"key 1", Some(b"value"));
+        write_raw_record(&truncated, 0, 1, b"key 2", Some(b"value"));
+        let data = fs::read(&truncated).unwrap();
+        fs::write(&truncated, &data[..data.len() - 3]).unwrap();
+
+        let mut records = WalIterator::new(corrupt).unwrap().records();
+        assert_eq!(records.next().unwrap().unwrap().key, b"key 1");
+        let err = records.next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(records.next().is_none());
+
+        let mut records = WalIterator::new(truncated).unwrap().records();
+        assert_eq!(records.next().unwrap().unwrap().key, b"key 1");
+        assert!(records.next().is_none());
+    }
+
+    #[test]
+    fn test_wal_corrupt_segment_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.wal");
+        write_corrupt_segment(&path);
+
+        let err = Wal::validate(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let res = Wal::iter_dir(dir.path()).unwrap()
+            .collect::<io::Result<Vec<_>>>();
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(Wal::changes_since(dir.path(), 0).is_err());
+        assert!(Wal::verify_replay(dir.path(), &MemTable::new()).is_err());
+    }
+
+    #[test]
+    fn test_wal_load_from_dir_legacy_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.wal");
+        write_legacy_record(&path, 0, 0, b"key 1", Some(b"value"));
+        write_legacy_record(&path, 1, 1, b"key 2", None);
+
+        let (wal, mem_table) = Wal::load_from_dir(dir.path()).unwrap();
+
+        assert_eq!(mem_table.get_value(b"key 1"), Some(&b"value"[..]));
+        assert!(mem_table.get(b"key 2").unwrap().deleted);
+        assert!(!path.exists());
+        let data = fs::read(&wal.path).unwrap();
+        assert_eq!(&data[..4], &SEGMENT_MAGIC);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_wal_load_from_dir_not_a_wal_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let junk = dir.path().join("1.wal");
+        fs::write(&junk, b"not a write-ahead log").unwrap();
+        let good = dir.path().join("2.wal");
+        write_raw_record(&good, 0, 0, b"key 1", Some(b"value"));
+        let good_data = fs::read(&good).unwrap();
+
+        let err = Wal::load_from_dir(dir.path()).err().unwrap();
+
+        let not_a_wal = err.get_ref().unwrap()
+            .downcast_ref::<NotAWalFile>()
+            .unwrap();
+        assert_eq!(not_a_wal.path, junk);
+        assert_eq!(fs::read(&junk).unwrap(), b"not a write-ahead log");
+        assert_eq!(fs::read(&good).unwrap(), good_data);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_wal_into_iter_sees_unflushed_header_and_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let entries: Vec<_> = Wal::new(dir.path()).unwrap()
+            .into_iter()
+            .collect();
+        assert!(entries.is_empty());
+
+        let mut wal = Wal::new(dir.path()).unwrap();
+        wal.set(b"key 1", b"value", 0).unwrap();
+
+        let entries = wal.into_iter().collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].key, b"key 1");
+    }
+
+    #[test]
+    fn test_wal_into_iter_open_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal = Wal::new(dir.path()).unwrap();
+        fs::remove_file(&wal.path).unwrap();
+
+        let entries: Vec<_> = wal.into_iter().collect();
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].as_ref().unwrap_err().kind(),
+                   ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_wal_load_from_dir_keeps_corrupt_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("1.wal");
+        write_raw_record(&first, 0, 0, b"key 0", Some(b"value"));
+        let corrupt = dir.path().join("2.wal");
+        write_corrupt_segment(&corrupt);
+        let corrupt_data = fs::read(&corrupt).unwrap();
+
+        let err = Wal::load_from_dir(dir.path()).err().unwrap();
+
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("2.wal"));
+        assert!(err.to_string().contains("checksum"));
+        assert!(first.exists());
+        assert_eq!(fs::read(&corrupt).unwrap(), corrupt_data);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_wal_load_from_dir_drops_truncated_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.wal");
+        write_raw_record(&path, 0, 0, b"key 1", Some(b"value"));
+        write_raw_record(&path, 0, 1, b"key 2", Some(b"value"));
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() - 3]).unwrap();
+
+        let (_, mem_table) = Wal::load_from_dir(dir.path()).unwrap();
+
+        assert_eq!(mem_table.len(), 1);
+        assert_eq!(mem_table.get_value(b"key 1"), Some(&b"value"[..]));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_wal_iter_dir_skips_empty_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("1.wal"), b"").unwrap();
+        let mut wal = Wal::from_path(&dir.path().join("2.wal")).unwrap();
+        wal.set(b"key 1", b"value", 10).unwrap();
+        wal.set(b"key 2", b"value", 20).unwrap();
+        wal.flush().unwrap();
+
+        let entries = Wal::iter_dir(dir.path()).unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        let changes: Vec<_> = Wal::changes_since(dir.path(), 10).unwrap()
+            .collect();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].key, b"key 2");
+    }
+}